@@ -1,5 +1,10 @@
 #![feature(drain_filter)]
-#![feature(variant_count)]
+
+use std::collections::BTreeMap;
+
+use rand::Rng;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 struct Location {
@@ -13,24 +18,35 @@ impl Location {
     }
 }
 
-fn rdtsc() -> u64 {
-    unsafe { core::arch::x86_64::_rdtsc() }
-}
-
 /// The maximum free size (as a power of two) to create in the free blocks
 const MAX_POW2_FREE_SIZE: u32 = 8;
 
 /// Maximum number of locations to allocate for the free block work set
 const FREE_BLOCKS_SIZE: u64 = 1024 * 16;
 
+/// Get the RNG seed to use for this run, preferring the first CLI arg, then the
+/// `BENCH_SEED` env var, and finally a fresh seed from the OS so a run can always
+/// be printed and replayed exactly
+fn get_seed() -> u64 {
+    if let Some(seed) = std::env::args().nth(1) {
+        return seed.parse().expect("Seed must be a valid u64");
+    }
+
+    if let Ok(seed) = std::env::var("BENCH_SEED") {
+        return seed.parse().expect("BENCH_SEED must be a valid u64");
+    }
+
+    rand::thread_rng().gen()
+}
+
 /// Create a random set of Locations returning the locations and the maximum allocation
-fn create_free_blocks() -> (Vec<Location>, u64) {
+fn create_free_blocks(rng: &mut ChaCha8Rng) -> (Vec<Location>, u64) {
     let mut result = Vec::new();
     let mut curr_addr = 0;
     let mut max_alloc = 0;
-    for _ in 0..(rdtsc() % FREE_BLOCKS_SIZE + 10) {
+    for _ in 0..(rng.gen_range(0..FREE_BLOCKS_SIZE) + 10) {
         // Randomly choose the next size for the allocation
-        let next_size = 2_u64.pow(rdtsc() as u32 % MAX_POW2_FREE_SIZE + 1);
+        let next_size = 2_u64.pow(rng.gen_range(1..=MAX_POW2_FREE_SIZE));
         max_alloc = max_alloc.max(next_size);
 
         // Add this block to the result
@@ -41,8 +57,8 @@ fn create_free_blocks() -> (Vec<Location>, u64) {
 
         /*
         // Randomly choose to have an empty gap
-        if rdtsc() % 2 == 0 {
-            curr_addr += 2_u32.pow(rdtsc() as u32 % 6 + 2);
+        if rng.gen_bool(0.5) {
+            curr_addr += 2_u32.pow(rng.gen_range(2..8));
         }
         */
     }
@@ -115,6 +131,236 @@ fn fourth_solution(free_blocks: &mut Vec<Location>, alloc: u64) -> Location {
     free_blocks.swap_remove(best_index.unwrap())
 }
 
+fn fifth_solution(
+    free_blocks: &mut [Vec<Location>; MAX_POW2_FREE_SIZE as usize + 1],
+    alloc: u64,
+) -> Location {
+    // `alloc.ilog2()` panics on 0 (no free block is ever that small, so bucket 0
+    // is a fine stand-in). Note this is the floor of `log2(alloc)`, not
+    // `alloc.next_power_of_two().ilog2()`: once blocks can be split and coalesced
+    // their lengths are no longer restricted to powers of two, and only the floor
+    // guarantees every bucket above `start` is entirely `>= alloc`.
+    let start = if alloc == 0 {
+        0
+    } else {
+        alloc.ilog2() as usize
+    };
+
+    // The starting bucket spans `[2^start, 2^{start+1})`, which may contain blocks
+    // smaller than `alloc`, so it still needs to be filtered.
+    if let Some((idx, _)) = free_blocks[start]
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.length >= alloc)
+        .min_by_key(|(_, b)| b.length)
+    {
+        return free_blocks[start].swap_remove(idx);
+    }
+
+    // Every higher bucket only holds blocks of at least `2^{class}` which is already
+    // `>= alloc`, so the first non-empty one is guaranteed to fit.
+    for bucket in &mut free_blocks[start + 1..] {
+        if let Some((idx, _)) = bucket.iter().enumerate().min_by_key(|(_, b)| b.length) {
+            return bucket.swap_remove(idx);
+        }
+    }
+
+    panic!("Not found")
+}
+
+/// Bucket a flat free list by size class, the layout [`fifth_solution`] searches.
+/// A coalesced block can grow past `MAX_POW2_FREE_SIZE`, so its class is clamped
+/// into the last bucket, which then holds "this size or bigger" instead of an
+/// exact power-of-two range.
+fn to_segregated_bins(
+    free_blocks: &[Location],
+) -> [Vec<Location>; MAX_POW2_FREE_SIZE as usize + 1] {
+    let mut bins: [Vec<Location>; MAX_POW2_FREE_SIZE as usize + 1] = Default::default();
+    for block in free_blocks {
+        let class = (block.length.ilog2() as usize).min(MAX_POW2_FREE_SIZE as usize);
+        bins[class].push(*block);
+    }
+    bins
+}
+
+/// Adapt [`fifth_solution`] to a flat free list, so it can be raced against the
+/// other strategies without them needing to know about its bucketed layout
+fn fifth_solution_vec(free_blocks: &mut Vec<Location>, alloc: u64) -> Location {
+    let mut bins = to_segregated_bins(free_blocks);
+    let block = fifth_solution(&mut bins, alloc);
+    *free_blocks = bins.into_iter().flatten().collect();
+    block
+}
+
+fn sixth_solution(free_blocks: &mut BTreeMap<u64, Vec<Location>>, alloc: u64) -> Location {
+    let key = *free_blocks.range(alloc..).next().expect("Not found").0;
+
+    let blocks = free_blocks.get_mut(&key).unwrap();
+    let block = blocks.pop().unwrap();
+    if blocks.is_empty() {
+        free_blocks.remove(&key);
+    }
+
+    block
+}
+
+/// Key a flat free list by length, the map [`sixth_solution`] searches
+fn to_btree_map(free_blocks: &[Location]) -> BTreeMap<u64, Vec<Location>> {
+    let mut map: BTreeMap<u64, Vec<Location>> = BTreeMap::new();
+    for block in free_blocks {
+        map.entry(block.length).or_default().push(*block);
+    }
+    map
+}
+
+/// Adapt [`sixth_solution`] to a flat free list, so it can be raced against the
+/// other strategies without them needing to know about its map-based layout
+fn sixth_solution_vec(free_blocks: &mut Vec<Location>, alloc: u64) -> Location {
+    let mut map = to_btree_map(free_blocks);
+    let block = sixth_solution(&mut map, alloc);
+    *free_blocks = map.into_values().flatten().collect();
+    block
+}
+
+/// Split an allocated `block` down to exactly `alloc` bytes, pushing any leftover
+/// remainder back onto `free_blocks`
+fn split_block(free_blocks: &mut Vec<Location>, block: Location, alloc: u64) -> Location {
+    if block.length == alloc {
+        return block;
+    }
+
+    free_blocks.push(Location::new(block.address + alloc, block.length - alloc));
+
+    Location::new(block.address, alloc)
+}
+
+/// Return `loc` to the free set, coalescing it with any free block that is
+/// physically adjacent to it on either side
+fn free_block(free_blocks: &mut Vec<Location>, loc: Location) {
+    let mut merged = loc;
+
+    if let Some(idx) = free_blocks
+        .iter()
+        .position(|b| b.address + b.length == merged.address)
+    {
+        let before = free_blocks.swap_remove(idx);
+        merged = Location::new(before.address, before.length + merged.length);
+    }
+
+    if let Some(idx) = free_blocks
+        .iter()
+        .position(|b| merged.address + merged.length == b.address)
+    {
+        let after = free_blocks.swap_remove(idx);
+        merged = Location::new(merged.address, merged.length + after.length);
+    }
+
+    free_blocks.push(merged);
+}
+
+/// Fragmentation observed in a free set: how many separate free blocks remain and
+/// the size of the largest one
+#[derive(Debug, Copy, Clone, Default)]
+struct Fragmentation {
+    free_block_count: usize,
+    largest_free_block: u64,
+}
+
+fn fragmentation(free_blocks: &[Location]) -> Fragmentation {
+    Fragmentation {
+        free_block_count: free_blocks.len(),
+        largest_free_block: free_blocks.iter().map(|b| b.length).max().unwrap_or(0),
+    }
+}
+
+/// One step of the randomized alloc/free workload in [`run_alloc_free_workload`].
+/// `Free` carries a ratio rather than an index so the same pre-rolled sequence of
+/// steps can be replayed against every strategy's own, independently sized, set
+/// of live allocations.
+enum Step {
+    Alloc(u64),
+    Free(f64),
+}
+
+/// Signature shared by every search strategy once adapted to operate over a
+/// plain `Vec<Location>` free list
+type Strategy = fn(&mut Vec<Location>, u64) -> Location;
+
+/// Search strategies raced against each other in [`run_alloc_free_workload`]
+const STRATEGIES: [(&str, Strategy); 6] = [
+    ("First", first_solution),
+    ("FilterSwapRemove", second_solution),
+    ("Fold", third_solution),
+    ("ForLoop", fourth_solution),
+    ("SegregatedBins", fifth_solution_vec),
+    ("BTreeBestFit", sixth_solution_vec),
+];
+
+/// Run the same randomized workload of interleaved allocations and frees once per
+/// search strategy, splitting and coalescing blocks as it goes, and report the
+/// worst fragmentation each strategy sees along the way
+fn run_alloc_free_workload(rng: &mut ChaCha8Rng) {
+    const STEPS: usize = 10_000;
+
+    let (free_blocks, max_allocation) = create_free_blocks(rng);
+
+    // Pre-roll the step sequence once so every strategy is driven by the exact
+    // same workload; any difference in fragmentation then comes only from the
+    // strategy itself, not from divergent randomness
+    let steps: Vec<Step> = (0..STEPS)
+        .map(|_| {
+            if rng.gen_bool(0.6) {
+                Step::Alloc(rng.gen_range(1..max_allocation))
+            } else {
+                Step::Free(rng.gen_range(0.0..1.0))
+            }
+        })
+        .collect();
+
+    for (name, strategy) in STRATEGIES {
+        let mut free_blocks = free_blocks.clone();
+        let mut live: Vec<Location> = Vec::new();
+        let mut worst_fragmentation = Fragmentation::default();
+
+        for step in &steps {
+            match step {
+                Step::Alloc(alloc) => {
+                    // Skip the step if nothing fits rather than let the strategy panic
+                    if free_blocks.iter().any(|b| b.length >= *alloc) {
+                        let block = strategy(&mut free_blocks, *alloc);
+
+                        let allocated = timeloop::time_work!(ProfileBlock::Split, {
+                            split_block(&mut free_blocks, block, *alloc)
+                        });
+
+                        live.push(allocated);
+                    }
+                }
+                Step::Free(ratio) => {
+                    if !live.is_empty() {
+                        let idx = ((*ratio * live.len() as f64) as usize).min(live.len() - 1);
+                        let loc = live.swap_remove(idx);
+
+                        timeloop::time_work!(ProfileBlock::Coalesce, {
+                            free_block(&mut free_blocks, loc)
+                        });
+                    }
+                }
+            }
+
+            let current = fragmentation(&free_blocks);
+            if current.free_block_count > worst_fragmentation.free_block_count {
+                worst_fragmentation = current;
+            }
+        }
+
+        println!(
+            "{name}: worst fragmentation over {STEPS} steps: {} free blocks, largest {} bytes",
+            worst_fragmentation.free_block_count, worst_fragmentation.largest_free_block
+        );
+    }
+}
+
 timeloop::impl_enum!(
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub enum ProfileBlock {
@@ -123,43 +369,60 @@ timeloop::impl_enum!(
         FilterSwapRemove,
         Fold,
         ForLoop,
+        SegregatedBins,
+        BTreeBestFit,
+        Split,
+        Coalesce,
     }
 );
 
 timeloop::create_profiler!(ProfileBlock);
 
 fn main() {
-    const NUM_PROFILE_BLOCKS: u64 = std::mem::variant_count::<ProfileBlock>() as u64;
+    // Number of search strategies raced against each other below. This is
+    // deliberately not `ProfileBlock`'s variant count: that enum also carries
+    // one-off categories (`CreateWork`, `Split`, `Coalesce`) that have nothing to
+    // do with sizing the per-iteration comparison loop.
+    const NUM_STRATEGIES: u64 = 6;
     const ITERS: usize = 10000;
 
+    let seed = get_seed();
+    println!("Seed: {seed}");
     println!("Iters: {ITERS}");
     println!("Max size of free blocks: {FREE_BLOCKS_SIZE}");
 
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
     timeloop::start_profiler!();
 
     // Run the benchmark for a number of iterations
     for _ in 0..ITERS {
         // Create the work for each of the test cases
-        let (mut work, alloc) = timeloop::time_work!(ProfileBlock::CreateWork, {
-            // Create a set of free blocks with the maximum address in this space
-            let (free_blocks, max_allocation) = create_free_blocks();
-            let alloc: u64 = rdtsc() % max_allocation;
+        let (mut work, mut bins_work, mut btree_work, alloc) =
+            timeloop::time_work!(ProfileBlock::CreateWork, {
+                // Create a set of free blocks with the maximum address in this space
+                let (free_blocks, max_allocation) = create_free_blocks(&mut rng);
+                let alloc: u64 = rng.gen_range(0..max_allocation);
+
+                // Clone the current work for all of the test cases
+                let work: Vec<_> = (0..NUM_STRATEGIES).map(|_| free_blocks.clone()).collect();
+
+                // The segregated-bins strategy works over its own bucketed layout
+                let bins_work = to_segregated_bins(&free_blocks);
 
-            // Clone the current work for all of the test cases
-            let work: Vec<_> = (0..NUM_PROFILE_BLOCKS)
-                .map(|_| free_blocks.clone())
-                .collect();
+                // The BTree strategy works over its own length-keyed map
+                let btree_work = to_btree_map(&free_blocks);
 
-            (work, alloc)
-        });
+                (work, bins_work, btree_work, alloc)
+            });
 
         // Reset the answers and finished result arrays
-        let mut answers = [Location::default(); NUM_PROFILE_BLOCKS as usize];
-        let mut finished = [false; NUM_PROFILE_BLOCKS as usize];
+        let mut answers = [Location::default(); NUM_STRATEGIES as usize];
+        let mut finished = [false; NUM_STRATEGIES as usize];
 
         // Call each test case in a random order
         while !finished.iter().all(|x| *x) {
-            let curr_test = (rdtsc() % NUM_PROFILE_BLOCKS) as usize;
+            let curr_test = rng.gen_range(0..NUM_STRATEGIES) as usize;
             if finished[curr_test] {
                 continue;
             }
@@ -187,7 +450,17 @@ fn main() {
                         fourth_solution(&mut curr_work, alloc)
                     })
                 }
-                _ => Location::default(),
+                4 => {
+                    timeloop::time_work!(ProfileBlock::SegregatedBins, {
+                        fifth_solution(&mut bins_work, alloc)
+                    })
+                }
+                5 => {
+                    timeloop::time_work!(ProfileBlock::BTreeBestFit, {
+                        sixth_solution(&mut btree_work, alloc)
+                    })
+                }
+                _ => unreachable!("curr_test is drawn from 0..NUM_STRATEGIES"),
             };
 
             answers[curr_test] = answer;
@@ -197,7 +470,16 @@ fn main() {
         assert!(answers[0] == answers[1]);
         assert!(answers[0] == answers[2]);
         assert!(answers[0] == answers[3]);
+        assert!(answers[0] == answers[4]);
+        // The BTree strategy can yield a different block than the others when
+        // multiple free blocks tie on length, since it pops from that length's
+        // bucket in whatever order the map holds them rather than scan order.
+        assert!(answers[0].length == answers[5].length);
     }
 
+    // Demonstrate the full alloc/free subsystem (splitting and coalescing) under a
+    // sustained, interleaved workload and report how fragmented it gets
+    run_alloc_free_workload(&mut rng);
+
     timeloop::print!();
 }